@@ -0,0 +1,203 @@
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use etherparse::Ipv4HeaderSlice;
+use tun_tap::{Iface, Mode};
+
+use crate::tcp::Connection;
+use crate::Quad;
+
+// 没有新包到达时，最多等这么久就醒一次，检查要不要重传/持续探测，顺带把排队的写数据送出去
+const TICK_INTERVAL_MS: i32 = 200;
+
+#[derive(Default)]
+struct Shared {
+    connections: HashMap<Quad, Connection>,
+    // 每个本地端口上，已经收到SYN并回了SYN-ACK、还没被 TcpListener::accept() 取走的连接
+    // （入队时三次握手的最后一个ACK可能还没到）
+    pending: HashMap<u16, VecDeque<Quad>>,
+}
+
+/// 整个TUN-based TCP协议栈：内部线程驱动收发循环、重传/持续定时器，
+/// 对外只暴露 bind()，好让上层应用像用socket一样 accept/read/write
+pub struct Interface {
+    shared: Arc<Mutex<Shared>>,
+    accept_cv: Arc<Condvar>,
+}
+
+impl Interface {
+    pub fn new() -> io::Result<Self> {
+        let iface =
+            Iface::without_packet_info("mytun", Mode::Tun).expect("Failed to create a TUN device");
+        let shared: Arc<Mutex<Shared>> = Default::default();
+        let accept_cv = Arc::new(Condvar::new());
+
+        let loop_shared = shared.clone();
+        let loop_cv = accept_cv.clone();
+        thread::spawn(move || {
+            if let Err(e) = packet_loop(iface, loop_shared, loop_cv) {
+                eprintln!("tcp stack terminated: {:?}", e);
+            }
+        });
+
+        Ok(Interface { shared, accept_cv })
+    }
+
+    /// 在某个本地端口上挂起监听；真正的三次握手由收发线程完成，这里只是登记一下，
+    /// 好让后面的 accept() 知道要去哪个队列里取新连接
+    pub fn bind(&self, port: u16) -> io::Result<TcpListener> {
+        self.shared.lock().unwrap().pending.entry(port).or_default();
+        Ok(TcpListener {
+            port,
+            shared: self.shared.clone(),
+            accept_cv: self.accept_cv.clone(),
+        })
+    }
+}
+
+pub struct TcpListener {
+    port: u16,
+    shared: Arc<Mutex<Shared>>,
+    accept_cv: Arc<Condvar>,
+}
+
+impl TcpListener {
+    /// 阻塞直到这个端口上有一个新连接：收到对方SYN、回了SYN-ACK就算，不等三次握手的最后一个ACK
+    pub fn accept(&self) -> TcpStream {
+        let mut shared = self.shared.lock().unwrap();
+        loop {
+            if let Some(quad) = shared.pending.get_mut(&self.port).and_then(VecDeque::pop_front) {
+                return TcpStream {
+                    quad,
+                    shared: self.shared.clone(),
+                };
+            }
+            shared = self.accept_cv.wait(shared).unwrap();
+        }
+    }
+}
+
+/// 一条已建立的连接，读写都通过锁一下共享的连接表来完成
+pub struct TcpStream {
+    quad: Quad,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl TcpStream {
+    /// 读取目前已经收到的数据，没有数据时返回0（不阻塞）
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.connections.get_mut(&self.quad) {
+            Some(conn) => conn.recv(buf),
+            None => 0,
+        }
+    }
+
+    /// 排队一段待发送的数据，真正发出去由收发线程的tick完成
+    pub fn write(&self, data: &[u8]) {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(conn) = shared.connections.get_mut(&self.quad) {
+            conn.send(data);
+        }
+    }
+
+    /// 对方是不是已经发来FIN了：read() 返回0字节时，靠这个区分"暂时没数据"
+    /// 和"对方关闭了连接，该调用close()了"。连接已经从表里摘掉也算对方关闭了
+    pub fn peer_closed(&self) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.connections.get_mut(&self.quad) {
+            Some(conn) => conn.is_peer_closed(),
+            None => true,
+        }
+    }
+
+    /// 主动关闭连接：排队一个FIN，真正发送由收发线程的tick完成
+    pub fn close(&self) {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(conn) = shared.connections.get_mut(&self.quad) {
+            conn.close();
+        }
+    }
+}
+
+fn packet_loop(mut iface: Iface, shared: Arc<Mutex<Shared>>, accept_cv: Arc<Condvar>) -> io::Result<()> {
+    let mut buf = vec![0; 1504]; // MTU + 4 for the header
+
+    loop {
+        {
+            let mut shared = shared.lock().unwrap();
+            shared.connections.retain(|_, c| !c.is_terminated());
+        }
+
+        // 给 iface.recv 套一个超时：没有包到达时，也要定期检查重传/持续定时器，顺带发送排队的数据
+        let mut pfd = libc::pollfd {
+            fd: iface.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut pfd, 1, TICK_INTERVAL_MS) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        } else if ready == 0 {
+            let mut shared = shared.lock().unwrap();
+            for conn in shared.connections.values_mut() {
+                conn.on_tick(&mut iface)?;
+            }
+            continue;
+        }
+
+        let nbytes = iface.recv(&mut buf)?;
+
+        match Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
+            Ok(iph) => {
+                let src = iph.source_addr();
+                let dst = iph.destination_addr();
+                // tcp=0x06 : 查看ip协议号列表：https://zh.wikipedia.org/wiki/IP%E5%8D%8F%E8%AE%AE%E5%8F%B7%E5%88%97%E8%A1%A8
+                let proto = iph.protocol();
+                if proto != 0x06 {
+                    // 不是tcp
+                    continue;
+                }
+
+                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
+                    Ok(tcph) => {
+                        // 从数据包的开头到tcp头结束
+                        let datai = iph.slice().len() + tcph.slice().len();
+                        let quad = Quad {
+                            src: (src, tcph.source_port()),
+                            dst: (dst, tcph.destination_port()),
+                        };
+                        let dst_port = tcph.destination_port();
+
+                        let mut shared = shared.lock().unwrap();
+                        match shared.connections.entry(quad) {
+                            Entry::Occupied(mut v) => {
+                                v.get_mut().on_packet(&mut iface, iph, tcph, &buf[datai..nbytes])?;
+                            }
+                            Entry::Vacant(v) => {
+                                if let Some(c) =
+                                    Connection::accept(&mut iface, iph, tcph, &buf[datai..nbytes])?
+                                {
+                                    v.insert(c);
+                                    // 只有登记过 bind() 的端口才有人在 accept()，没人等着的话这里
+                                    // 只是攒着，不会有人来取
+                                    shared.pending.entry(dst_port).or_default().push_back(quad);
+                                    accept_cv.notify_all();
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("ignoring weird tcp packet {:?}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("ignoring weird packet {:?}", e);
+            }
+        }
+    }
+}
@@ -1,13 +1,8 @@
-use std::{
-    collections::{hash_map::Entry, HashMap},
-    io,
-    net::Ipv4Addr,
-};
+use std::{io, net::Ipv4Addr, thread, time::Duration};
 
-use etherparse::Ipv4HeaderSlice;
-use tcp::Connection;
-use tun_tap::{Iface, Mode};
+use interface::Interface;
 
+mod interface;
 mod tcp;
 
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
@@ -16,69 +11,33 @@ pub struct Quad {
     dst: (Ipv4Addr, u16),
 }
 
-fn main() -> io::Result<()> {
-    let mut connections: HashMap<Quad, Connection> = Default::default();
+// 没有新数据可读时，读线程歇一会儿再来看看，别忙等
+const READ_POLL_INTERVAL_MS: u64 = 50;
 
-    // mode==tun 表示网络层数据，前置4字节数据，前两个字节是flags，后两个字节是协议，ipv4，ivp6
-    let mut iface =
-        Iface::without_packet_info("mytun", Mode::Tun).expect("Failed to create a TUN device");
-    let mut buf = vec![0; 1504]; // MTU + 4 for the header
+// TUN设备收发、重传/持续定时器都跑在 Interface 内部的线程里；这里只是个跑在它上面的
+// 应用：对每个连进来的 TcpStream 起一个线程，原样把收到的数据回显回去
+fn main() -> io::Result<()> {
+    let iface = Interface::new()?;
+    let listener = iface.bind(8080)?;
 
     loop {
-        let nbytes = iface.recv(&mut buf)?;
-        // 解析前置的4字节数据，首部中的字段均以大端序包装
-        // let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
-        // let proto = u16::from_be_bytes([buf[2], buf[3]]);
-        // // 查看协议号：https://en.wikipedia.org/wiki/EtherType
-        // if proto != 0x0800 {
-        //     // 不是ipv4
-        //     continue;
-        // }
-
-        match Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
-                let src = iph.source_addr();
-                let dst = iph.destination_addr();
-                // tcp=0x06 : 查看ip协议号列表：https://zh.wikipedia.org/wiki/IP%E5%8D%8F%E8%AE%AE%E5%8F%B7%E5%88%97%E8%A1%A8
-                let proto = iph.protocol();
-                if proto != 0x06 {
-                    // 不是tcp
-                    continue;
-                }
-
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
-                    Ok(tcph) => {
-                        // 从数据包的开头到tcp头结束
-                        let datai = iph.slice().len() + tcph.slice().len();
-                        match connections.entry(Quad {
-                            src: (src, tcph.source_port()),
-                            dst: (dst, tcph.destination_port()),
-                        }) {
-                            Entry::Occupied(mut v) => {
-                                v.get_mut().on_packet(
-                                    &mut iface,
-                                    iph,
-                                    tcph,
-                                    &buf[datai..nbytes],
-                                )?;
-                            }
-                            Entry::Vacant(v) => {
-                                if let Some(c) =
-                                    Connection::accept(&mut iface, iph, tcph, &buf[datai..nbytes])?
-                                {
-                                    v.insert(c);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("ignoring weird tcp packet {:?}", e);
+        let stream = listener.accept();
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            loop {
+                let n = stream.read(&mut buf);
+                if n == 0 {
+                    if stream.peer_closed() {
+                        // 对方已经发过FIN了，没有更多数据可读：回应着关闭我们这一半，
+                        // 不然连接会永远卡在CloseWait，HashMap<Quad,Connection>就漏掉了
+                        stream.close();
+                        break;
                     }
+                    thread::sleep(Duration::from_millis(READ_POLL_INTERVAL_MS));
+                    continue;
                 }
+                stream.write(&buf[..n]);
             }
-            Err(e) => {
-                eprintln!("ignoring weird packet {:?}", e);
-            }
-        }
+        });
     }
 }
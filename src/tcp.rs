@@ -1,19 +1,53 @@
-use std::cmp::{min, Ordering::*};
+use std::cmp::{max, min, Ordering::*};
+use std::collections::{BTreeMap, VecDeque};
 use std::io::{self, Write};
-use std::usize::MIN;
+use std::mem;
+use std::time::{Duration, Instant};
 use tun_tap::Iface;
 
+// 2*MSL（Maximum Segment Lifetime），TIME_WAIT 状态需要保持这么久，才能确保对端的重复报文在网络中消亡
+const MSL: Duration = Duration::from_secs(30);
+
+// RTO（重传超时）的上下限，避免因为一次异常的RTT样本把超时算得过短或过长
+const MIN_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+// 我们自己在SYN-ACK里通告的MSS，也就是我们愿意一次接收多少字节
+const DEFAULT_MSS: u16 = 1460;
+
+// 对方没有携带MSS选项时，RFC793规定的兜底值
+const FALLBACK_MSS: u16 = 536;
+
+// 持续定时器（persist timer）的起始间隔和退避上限：对方通告零窗口时，
+// 靠这个定时器周期性探测，而不是永远等一个不会再来的窗口更新
+const PERSIST_MIN: Duration = Duration::from_secs(1);
+const PERSIST_MAX: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub enum State {
     SynRcvd,
     Estab,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+    Closed,
 }
 
 impl State {
     fn is_synchronized(&self) -> bool {
         match *self {
             State::SynRcvd => false,
-            State::Estab => true,
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::TimeWait
+            | State::CloseWait
+            | State::LastAck => true,
+            State::Closed => false,
         }
     }
 }
@@ -25,6 +59,116 @@ pub struct Connection {
     recv: RecvSequenceSpace,
     ip: etherparse::Ipv4Header,
     tcp: etherparse::TcpHeader,
+
+    // 本端调用 close() 后，发送的FIN所占的序列号之后的下一个序列号；
+    // 用它来判断我们的FIN是否已经被对端确认（SND.UNA 走到这个值）
+    closed_at: Option<u32>,
+    // close() 已经被调用，但 unsent 里还有数据没发完：FIN得等 write() 把它们都送出去
+    // 之后才能带上，不然FIN会插到还排着队的字节前面，不再是流的最后一个字节
+    fin_pending: bool,
+    // 应用层调用了 close()，但当时没有 iface 可用（close() 不持有它）：
+    // 记一下，等下一次 on_tick 拿到 iface 再真正推进状态机、发FIN
+    close_requested: bool,
+    // 进入 TimeWait 状态的时间点，用来判断 2*MSL 是否超时
+    time_wait_started: Option<Instant>,
+
+    // 乱序到达、还没能拼到 recv.nxt 后面的分片，按起始序列号排序存放
+    reassembly: BTreeMap<u32, Vec<u8>>,
+    // 已经按序拼接好、还没被应用层读走的数据
+    incoming: VecDeque<u8>,
+    // 对端FIN占用的序列号；在它之前的数据没到齐时先记下来，等 recv.nxt 追上了再处理
+    fin_seq: Option<u32>,
+
+    // 已经发出去、还没被确认的字节（send.una..send.nxt），重传时从这里面取数据
+    unacked: VecDeque<u8>,
+    // 排队等着发、但还没被 write() 送出去的字节（可用窗口/拥塞窗口不够时被顶下来的部分）
+    unsent: VecDeque<u8>,
+    // 持续定时器下一次到期的时间点；None 表示没在计时（窗口不是零，或者没有数据排队）
+    persist_due: Option<Instant>,
+    // 持续定时器当前的退避间隔，每探测一次没打开窗口就翻倍，封顶 PERSIST_MAX
+    persist_backoff: Duration,
+    // 每个还未确认的起始序列号第一次被发送的时间，以及这次发送是不是一次重传
+    // (Karn算法：重传过的段不能用来采样RTT)
+    send_times: BTreeMap<u32, (Instant, bool)>,
+    // 平滑RTT、RTT方差（Jacobson算法），None表示还没有样本
+    srtt: Option<Duration>,
+    rttvar: Option<Duration>,
+    // 当前的重传超时时间
+    rto: Duration,
+
+    // Reno拥塞控制：拥塞窗口、慢启动阈值（单位都是字节）
+    cwnd: u32,
+    ssthresh: u32,
+    // 连续收到几个没有推进 send.una 的重复ACK了，凑够3个就触发快速重传
+    dup_acks: u32,
+
+    // 对方允许我们一次发送多少字节，从对方SYN里的MSS选项读出来，没有就用536兜底
+    mss: u16,
+    // 对方声明的窗口缩放因子（左移位数），解释对方发来的 window_size() 时要乘上去
+    snd_wnd_shift: u8,
+    // 我们自己声明的窗口缩放因子，写进我们发出去的SYN-ACK里
+    rcv_wnd_shift: u8,
+    // 对方要求启用时间戳选项，之后我们发的每个段都要带上
+    timestamps_enabled: bool,
+    // 对方最近一次发来的时间戳，回显在我们下一个发出去的段里
+    ts_recent: u32,
+    // 连接建立的时间点，当作我们自己时间戳时钟的起点
+    ts_start: Option<Instant>,
+
+    // 上一次实际发出去的段里，我们告诉对方的 recv.nxt/recv.wnd 是多少；
+    // on_tick 拿它判断这次心跳有没有新信息要报，没有就不白白发一个重复ACK
+    last_acked_nxt: u32,
+    last_advertised_wnd: u32,
+}
+
+/// 协商出来的、解析自SYN段选项区的三个字段
+#[derive(Default)]
+struct TcpOptions {
+    mss: Option<u16>,
+    window_scale: Option<u8>,
+    // (TSval, TSecr)
+    timestamps: Option<(u32, u32)>,
+}
+
+/// 手动解析20字节固定首部之后的选项区，只认识 kind 0/1（填充）、2（MSS）、3（窗口缩放）、8（时间戳）
+fn parse_tcp_options(mut options: &[u8]) -> TcpOptions {
+    let mut result = TcpOptions::default();
+
+    while !options.is_empty() {
+        match options[0] {
+            0 => break, // End of Option List
+            1 => options = &options[1..], // No-Op，只占一个字节
+            2 if options.len() >= 4 => {
+                result.mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                options = &options[4..];
+            }
+            3 if options.len() >= 3 => {
+                result.window_scale = Some(options[2]);
+                options = &options[3..];
+            }
+            8 if options.len() >= 10 => {
+                let tsval = u32::from_be_bytes(options[2..6].try_into().unwrap());
+                let tsecr = u32::from_be_bytes(options[6..10].try_into().unwrap());
+                result.timestamps = Some((tsval, tsecr));
+                options = &options[10..];
+            }
+            // 不认识的kind（比如Linux常发的SACK-permitted，kind=4）：每个TLV选项的
+            // 第二个字节都是它自己的总长度，按这个长度跳过去，不能直接break，
+            // 不然排在它后面的Timestamps/WindowScale会被一起丢掉
+            _ if options.len() >= 2 => {
+                let len = options[1] as usize;
+                if len < 2 || len > options.len() {
+                    // 长度字段本身不像话，没法再往前走了
+                    break;
+                }
+                options = &options[len..];
+            }
+            // 连长度字节都不够了，没法再解析
+            _ => break,
+        }
+    }
+
+    result
 }
 
 /// State Of Send Sequence Space (RFC 793 s3.2 F5)  发送序列空间
@@ -52,10 +196,10 @@ struct SendSequenceSpace {
 
 ///Receive Sequence Space (RFC 793 s3.2 F5) 接收序列空间
 ///
-///                 1          2          3      
+///                 1          2          3
 ///             ----------|----------|----------
-///                     RCV.NXT    RCV.NXT        
-///                             +RCV.WND        
+///                     RCV.NXT    RCV.NXT
+///                             +RCV.WND
 /// 1 - old sequence numbers which have been acknowledged  // 已确认的旧序列号
 /// 2 - sequence numbers allowed for new reception         // 允许新接收的序列号
 /// 3 - future sequence numbers which are not yet allowed  // 尚未允许的未来序列号
@@ -63,7 +207,7 @@ struct SendSequenceSpace {
 #[derive(Default)]
 struct RecvSequenceSpace {
     nxt: u32, // RCV.NXT - receive next
-    wnd: u16, // RCV.WND - receive window
+    wnd: u32, // RCV.WND - receive window (已经按窗口缩放因子折算成真实字节数)
     up: bool, // RCV.UP  - receive urgent pointer
     irs: u32, // IRS     - initial receive sequence number
 }
@@ -86,12 +230,26 @@ impl Connection {
             return Ok(None);
         }
 
+        let opts = parse_tcp_options(tcph.options());
+
         let mut conn = Connection::default();
         conn.state = State::SynRcvd;
 
+        // 对方允许我们发多大的段，没带MSS选项就用RFC793的兜底值536
+        conn.mss = opts.mss.unwrap_or(FALLBACK_MSS);
+        conn.snd_wnd_shift = opts.window_scale.unwrap_or(0);
+        conn.rcv_wnd_shift = 0; // 这个demo里我们自己不申请放大接收窗口
+        conn.timestamps_enabled = opts.timestamps.is_some();
+        if let Some((tsval, _)) = opts.timestamps {
+            conn.ts_recent = tsval;
+        }
+        conn.ts_start = Some(Instant::now());
+
         // keep track of sender info
         conn.recv.nxt = tcph.sequence_number() + 1;
-        conn.recv.wnd = tcph.window_size();
+        // RFC 7323: SYN里的窗口字段永远不做缩放（缩放因子本身就是在这个SYN里协商的，
+        // 握手还没完成，不能拿刚谈好的shift去折算这个SYN自己的窗口）
+        conn.recv.wnd = tcph.window_size() as u32;
         conn.recv.irs = tcph.sequence_number();
 
         // decide on stuff we're sending them
@@ -99,6 +257,10 @@ impl Connection {
         conn.send.una = conn.send.iss;
         conn.send.nxt = conn.send.una + 1;
         conn.send.wnd = 10;
+        conn.rto = MIN_RTO;
+        // 慢启动：从一个MSS开始，ssthresh先设成一个很大的值
+        conn.cwnd = conn.mss as u32;
+        conn.ssthresh = u32::MAX;
 
         // need to start establishing a connection
         // 先拼装tcp的包头
@@ -111,6 +273,8 @@ impl Connection {
         syn_ack.syn = true; // 发给客户端的 syn
         syn_ack.ack = true; // 发给客户端的 ack
         syn_ack.acknowledgment_number = conn.recv.nxt;
+        // MSS/窗口缩放（以及时间戳，如果对方要求的话）会在 send_segment 里根据
+        // syn_ack.syn 自动带上，见下面 write() 调用
 
         // 拼装ip包头
         conn.ip = etherparse::Ipv4Header::new(
@@ -143,9 +307,148 @@ impl Connection {
         Ok(())
     }
 
-    pub fn write(&mut self, iface: &mut Iface, payload: &[u8]) -> io::Result<usize> {
-        self.tcp.sequence_number = self.send.nxt;
+    /// 应用层请求关闭连接：不持有 iface，所以这里只记一个标记，真正推进状态机/发FIN
+    /// 留给下一次 on_tick（它手上有 iface）去做，见 begin_close()
+    pub fn close(&mut self) {
+        self.close_requested = true;
+    }
+
+    /// 真正开始关闭：推进状态机（Estab -> FinWait1，CloseWait -> LastAck），
+    /// 并把FIN排上队；FIN具体什么时候真的带着发出去，由 write() 决定——
+    /// 必须等 unsent 里排队的数据全部交出去之后，FIN才能是这个流的最后一个字节
+    fn begin_close(&mut self, iface: &mut Iface) -> io::Result<()> {
+        match self.state {
+            State::SynRcvd | State::Estab => {
+                self.state = State::FinWait1;
+            }
+            State::CloseWait => {
+                self.state = State::LastAck;
+            }
+            // 已经在关闭流程中了，不需要重复发送FIN
+            _ => return Ok(()),
+        }
+
+        self.fin_pending = true;
+        self.write(iface, &[])?;
+
+        Ok(())
+    }
+
+    /// 连接是否已经可以从 Interface 收发线程的连接表（HashMap<Quad, Connection>）中移除了
+    /// TimeWait 状态下，2*MSL 计时器到期后也会被当成 Closed
+    pub(crate) fn is_terminated(&mut self) -> bool {
+        if let State::TimeWait = self.state {
+            if let Some(started) = self.time_wait_started {
+                if started.elapsed() >= MSL * 2 {
+                    self.state = State::Closed;
+                }
+            }
+        }
+        matches!(self.state, State::Closed)
+    }
+
+    /// 对方是不是已经发过FIN了：应用层拿这个来判断“读到0字节”到底是暂时没数据，
+    /// 还是对方确实关闭了连接、该调用 close() 回应了
+    pub(crate) fn is_peer_closed(&self) -> bool {
+        matches!(
+            self.state,
+            State::CloseWait | State::LastAck | State::Closing | State::TimeWait | State::Closed
+        )
+    }
+
+    /// 把一个已经确认落在接收窗口内的数据段存起来；能和 recv.nxt 接上的部分
+    /// 会被立刻搬进 incoming，搬完后 recv.nxt/recv.wnd 也跟着推进/收缩
+    fn insert_segment(&mut self, seqn: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        // seqn 相对 recv.nxt 的偏移；负数说明这段数据里有一部分我们已经收过了
+        let offset = seqn.wrapping_sub(self.recv.nxt) as i32;
+        let (seqn, data) = if offset < 0 {
+            let already_seen = (-offset) as usize;
+            if already_seen >= data.len() {
+                // 整个段都是重复数据，丢掉
+                return;
+            }
+            (self.recv.nxt, &data[already_seen..])
+        } else {
+            (seqn, data)
+        };
+        let (mut seqn, mut data) = (seqn, data.to_vec());
+
+        // 左边已经有一个乱序分片跟这段有重叠：把重叠的部分也从这段里切掉，
+        // 不然下面按key去重叠分片会留下一段两头都够不着的数据，永远拼不进 incoming
+        if let Some((&prev_seq, prev_data)) = self.reassembly.range(..=seqn).next_back() {
+            let prev_end = prev_seq.wrapping_add(prev_data.len() as u32);
+            let overlap = prev_end.wrapping_sub(seqn) as i32;
+            if overlap > 0 {
+                let overlap = overlap as usize;
+                if overlap >= data.len() {
+                    // 整段都已经在更早那个分片里了
+                    return;
+                }
+                seqn = prev_end;
+                data.drain(..overlap);
+            }
+        }
+
+        // 新来的这段可能整个或者部分盖住了右边已经缓存的分片：把被盖住的部分丢掉，
+        // 盖不住的尾巴重新挂到新的起点上，保证 reassembly 里不存在互相重叠的key
+        let end = seqn.wrapping_add(data.len() as u32);
+        let covered: Vec<u32> = self.reassembly.range(seqn..end).map(|(&k, _)| k).collect();
+        for key in covered {
+            let existing = self.reassembly.remove(&key).unwrap();
+            let existing_end = key.wrapping_add(existing.len() as u32);
+            if existing_end > end {
+                let tail = existing[(existing.len() - (existing_end - end) as usize)..].to_vec();
+                self.reassembly.insert(end, tail);
+            }
+        }
+
+        self.reassembly.insert(seqn, data);
+
+        // 只要缺口补上了，就把连续的分片依次拼进 incoming，只 ACK 到连续的边界
+        while let Some(seg) = self.reassembly.remove(&self.recv.nxt) {
+            self.recv.nxt = self.recv.nxt.wrapping_add(seg.len() as u32);
+            self.recv.wnd = self.recv.wnd.saturating_sub(seg.len() as u32);
+            self.incoming.extend(seg);
+        }
+    }
+
+    /// 把 payload 从指定的序列号原样发出去，不触碰 send.una/send.nxt/unacked/send_times，
+    /// 供 write()（新数据）和 retransmit()（旧数据）共用
+    fn send_segment(&mut self, iface: &mut Iface, seq: u32, payload: &[u8]) -> io::Result<usize> {
+        self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+        // 把当前真实的接收窗口告诉对方，不然对方会一直用握手时那个过时的窗口发数据，
+        // 迟早把我们不存在的空间塞满（我们自己不用窗口缩放，rcv_wnd_shift恒为0，这里仍然按它折算，保持和对端的对称）
+        self.tcp.window_size = min(self.recv.wnd >> self.rcv_wnd_shift, u16::MAX as u32) as u16;
+
+        // MSS/窗口缩放只在SYN段上出现；时间戳选项（如果对方要求了）则每个段都带，好采样RTT
+        let mut options = Vec::new();
+        if self.tcp.syn {
+            options.push(etherparse::TcpOptionElement::MaximumSegmentSize(
+                DEFAULT_MSS,
+            ));
+            options.push(etherparse::TcpOptionElement::WindowScale(
+                self.rcv_wnd_shift,
+            ));
+        }
+        if self.timestamps_enabled {
+            let tsval = self
+                .ts_start
+                .map(|t| t.elapsed().as_millis() as u32)
+                .unwrap_or(0);
+            options.push(etherparse::TcpOptionElement::Timestamp(
+                tsval,
+                self.ts_recent,
+            ));
+        }
+        self.tcp
+            .set_options(&options)
+            .expect("tcp options too large");
+
         self.ip
             .set_payload_len(self.tcp.header_len() as usize + payload.len());
 
@@ -168,21 +471,207 @@ impl Connection {
         let unwritten = unwritten.len(); // 剩余的空间
         iface.send(&buf[..buf.len() - unwritten])?;
 
+        self.tcp.syn = false;
+        self.tcp.fin = false;
+
+        // 记下这次实际告诉对方的 recv.nxt/recv.wnd，给 on_tick 判断"这次心跳有没有新信息"用
+        self.last_acked_nxt = self.recv.nxt;
+        self.last_advertised_wnd = self.recv.wnd;
+
+        Ok(payload_bytes)
+    }
+
+    /// 把 payload 排进 unsent，再从 unsent 头部发出一个段，长度不超过可用窗口和MSS；
+    /// 每次调用只发这一个段，剩下的留在 unsent 里，由之后的 on_tick（零窗口时则是持续定时器）接着发
+    pub fn write(&mut self, iface: &mut Iface, payload: &[u8]) -> io::Result<usize> {
+        self.unsent.extend(payload);
+
+        let seq = self.send.nxt;
+        let had_syn = self.tcp.syn;
+
+        // usable window = min(send.wnd, cwnd) - 已经在飞的字节数，从不多发
+        let in_flight = self.send.nxt.wrapping_sub(self.send.una);
+        let usable = min(self.send.wnd, self.cwnd).saturating_sub(in_flight) as usize;
+        let len = min(usable, min(self.unsent.len(), self.mss as usize));
+        let payload: Vec<u8> = self.unsent.iter().take(len).copied().collect();
+
+        // FIN只有在这次把 unsent 剩下的字节全部交给 send_segment 之后才能带上，
+        // 不然FIN会插到还没发出去的数据前面，不再是这个流最后一个字节
+        let send_fin = self.fin_pending && len == self.unsent.len();
+        self.tcp.fin = send_fin;
+
+        let payload_bytes = self.send_segment(iface, seq, &payload)?;
+        self.unsent.drain(..payload_bytes);
+
+        // 占用了序列号的段才值得记下来重传：有数据，或者有SYN/FIN
+        if payload_bytes > 0 || had_syn || send_fin {
+            self.send_times.insert(seq, (Instant::now(), false));
+        }
+        if payload_bytes > 0 {
+            self.unacked.extend(&payload[..payload_bytes]);
+        }
+
         self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32);
 
-        if self.tcp.syn {
+        if had_syn {
             self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp.syn = false;
         }
 
-        if self.tcp.fin {
+        if send_fin {
             self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp.fin = false;
+            self.fin_pending = false;
+            // 记下FIN之后的下一个序列号，等 SND.UNA 追上它就说明我们的FIN被对端确认了
+            self.closed_at = Some(self.send.nxt);
         }
 
+        self.update_persist_timer();
+
         Ok(payload_bytes)
     }
 
+    /// 对方的窗口是不是零、还有数据排着队没发出去：是的话就该让持续定时器盯着；
+    /// 不是的话（窗口打开了，或者没数据可发）就把计时器撤掉
+    fn update_persist_timer(&mut self) {
+        if self.send.wnd == 0 && !self.unsent.is_empty() {
+            if self.persist_due.is_none() {
+                self.persist_backoff = PERSIST_MIN;
+                self.persist_due = Some(Instant::now() + self.persist_backoff);
+            }
+        } else {
+            self.persist_due = None;
+            self.persist_backoff = PERSIST_MIN;
+        }
+    }
+
+    /// 持续定时器到期：不管对方通告的零窗口，强行送一个字节过去，逼对方在回的ACK里
+    /// 重新报告窗口（RFC793 persist timer / BSD的 t_force）
+    fn send_persist_probe(&mut self, iface: &mut Iface) -> io::Result<()> {
+        if let Some(byte) = self.unsent.pop_front() {
+            let seq = self.send.nxt;
+            self.send_segment(iface, seq, &[byte])?;
+            // 探测包本来就是顶着零窗口硬发的，不能拿它的RTT来采样
+            self.send_times.insert(seq, (Instant::now(), true));
+            self.unacked.push_back(byte);
+            self.send.nxt = self.send.nxt.wrapping_add(1);
+        }
+
+        self.persist_backoff = min(self.persist_backoff * 2, PERSIST_MAX);
+        self.persist_due = Some(Instant::now() + self.persist_backoff);
+
+        Ok(())
+    }
+
+    /// Jacobson算法更新平滑RTT/RTT方差，并据此重新计算RTO
+    fn update_rtt(&mut self, rtt: Duration) {
+        match (self.srtt, self.rttvar) {
+            (Some(srtt), Some(rttvar)) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = Some(rttvar.mul_f64(3.0 / 4.0) + delta.mul_f64(1.0 / 4.0));
+                self.srtt = Some(srtt.mul_f64(7.0 / 8.0) + rtt.mul_f64(1.0 / 8.0));
+            }
+            _ => {
+                // 第一个RTT样本
+                self.srtt = Some(rtt);
+                self.rttvar = Some(rtt / 2);
+            }
+        }
+
+        let rto = self.srtt.unwrap() + self.rttvar.unwrap() * 4;
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// 把 send.una 所在的那个段原样重发一遍，不碰 cwnd/ssthresh；
+    /// 供 retransmit()（超时）和 fast_retransmit()（重复ACK）共用，
+    /// 两者对拥塞窗口的反应不一样，不能在这里帮它们做决定
+    fn resend_unacked(&mut self, iface: &mut Iface) -> io::Result<()> {
+        let seq = self.send.una;
+        let len = min(self.unacked.len(), self.mss as usize);
+        let payload: Vec<u8> = self.unacked.iter().take(len).copied().collect();
+
+        // 数据正好发完接着就是我们的FIN，顺便把FIN也带上
+        if matches!(self.closed_at, Some(closed_at) if seq.wrapping_add(len as u32) == closed_at.wrapping_sub(1))
+        {
+            self.tcp.fin = true;
+        }
+
+        self.send_segment(iface, seq, &payload)?;
+
+        // Karn规则：这一条是重传来的，确认了也不能拿来采样RTT
+        self.send_times.insert(seq, (Instant::now(), true));
+
+        Ok(())
+    }
+
+    /// send.una 所在的那个段超过 RTO 还没被确认，就从 send.una 开始重新发一遍
+    fn retransmit(&mut self, iface: &mut Iface) -> io::Result<()> {
+        self.resend_unacked(iface)?;
+
+        // 指数退避
+        self.rto = min(self.rto * 2, MAX_RTO);
+
+        // 超时意味着丢包：ssthresh减半，cwnd回到一个MSS重新慢启动
+        self.ssthresh = max(self.cwnd / 2, 2 * self.mss as u32);
+        self.cwnd = self.mss as u32;
+
+        Ok(())
+    }
+
+    /// 收到三个重复ACK：在RTO到期之前就认定丢包了，立刻重传并减半拥塞窗口
+    /// （不是超时，所以不重置RTO，也不让cwnd掉回一个MSS重新慢启动）
+    fn fast_retransmit(&mut self, iface: &mut Iface) -> io::Result<()> {
+        self.ssthresh = max(self.cwnd / 2, 2 * self.mss as u32);
+        self.cwnd = self.ssthresh;
+        self.resend_unacked(iface)
+    }
+
+    /// Interface 收发线程在 poll 超时（一段时间没有包到达）时调用：检查 send.una 是不是等了超过一个RTO还没被确认、
+    /// 持续定时器是不是到期了，顺带把应用层排进 unsent 的数据尽量送出去
+    pub fn on_tick(&mut self, iface: &mut Iface) -> io::Result<()> {
+        // close() 调用时手上没有 iface，只能先记个标记，这里是第一个能拿到 iface 的地方
+        if mem::take(&mut self.close_requested) {
+            self.begin_close(iface)?;
+        }
+
+        if let Some(&(sent_at, _)) = self.send_times.get(&self.send.una) {
+            if sent_at.elapsed() > self.rto {
+                self.retransmit(iface)?;
+            }
+        }
+
+        if matches!(self.persist_due, Some(due) if Instant::now() >= due) {
+            self.send_persist_probe(iface)?;
+        }
+
+        // 每个tick都无条件发一个空ACK，对一条闲着的连接（Estab空转、TimeWait的整个2MSL）
+        // 来说就是纯噪音：没有数据要发、没有FIN要发、也没有新的 recv.nxt/recv.wnd 要告诉对方，
+        // 这时候跳过，等真有新信息（数据排队、FIN、收到新段、窗口变化）时再发
+        let has_news = self.recv.nxt != self.last_acked_nxt || self.recv.wnd != self.last_advertised_wnd;
+        if !self.unsent.is_empty() || self.fin_pending || has_news {
+            self.write(iface, &[])?;
+        }
+
+        Ok(())
+    }
+
+    /// 应用层写数据：只是排进 unsent，真正发送由 write()/on_tick 在窗口和拥塞窗口允许的
+    /// 范围内完成；不需要调用者持有 Iface
+    pub fn send(&mut self, data: &[u8]) {
+        self.unsent.extend(data);
+    }
+
+    /// 应用层读数据：把已经按序收到、还没被读走的字节拷进 buf，返回实际读到的字节数；
+    /// 读走多少字节，接收窗口就重新打开多少，不然对方迟早会把窗口耗尽导致连接卡死。
+    /// 新窗口不会立刻推给对方——等下一个我们发出去的段（通常是下个tick；on_packet 收到数据本身不回ACK）
+    /// 把 recv.wnd 带出去，对方自然就看到窗口重新打开了
+    pub fn recv(&mut self, buf: &mut [u8]) -> usize {
+        let n = min(buf.len(), self.incoming.len());
+        for (slot, byte) in buf.iter_mut().zip(self.incoming.drain(..n)) {
+            *slot = byte;
+        }
+        self.recv.wnd = self.recv.wnd.saturating_add(n as u32);
+        n
+    }
+
     pub fn on_packet(
         &mut self,
         iface: &mut Iface,
@@ -210,11 +699,71 @@ impl Connection {
         // 0                                                                    0
         // |--N----------------------------------------------------------U--A-->|
 
+        if let State::Closed = self.state {
+            return Ok(());
+        }
+
         let ackn = tcph.acknowledgment_number();
-        if !is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+        // 放行 ackn == una（重复ACK），好让下面统计到3个就能触发快速重传
+        if ackn != self.send.una
+            && !is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1))
+        {
             return Ok(());
         }
 
+        let una = self.send.una;
+        self.send.una = ackn;
+
+        let acked = ackn.wrapping_sub(una) as usize;
+        if acked > 0 {
+            // 这段已经被确认了，扔出 unacked 队列
+            let drain_len = min(acked, self.unacked.len());
+            self.unacked.drain(..drain_len);
+
+            // 只有没重传过的段才能用来采样RTT（Karn算法）
+            if let Some((sent_at, retransmitted)) = self.send_times.remove(&una) {
+                if !retransmitted {
+                    self.update_rtt(sent_at.elapsed());
+                }
+            }
+            // 顺带清掉这次ACK覆盖范围内残留的其它记录（正常只会有上面那一条）
+            let now_una = self.send.una;
+            self.send_times
+                .retain(|&seq, _| !is_between_wrapped(una.wrapping_sub(1), seq, now_una));
+
+            // Reno: 慢启动每个ACK加一个MSS，拥塞避免每个ACK加 MSS*MSS/cwnd
+            if self.cwnd < self.ssthresh {
+                self.cwnd = self.cwnd.saturating_add(self.mss as u32);
+            } else {
+                let growth = (self.mss as u32).saturating_mul(self.mss as u32) / self.cwnd;
+                self.cwnd = self.cwnd.saturating_add(growth.max(1));
+            }
+            self.dup_acks = 0;
+        } else if data.is_empty() && !tcph.syn() && !tcph.fin() {
+            // RFC 5681: 真正算一次重复ACK，还要求对方通告的窗口没变、而且我们确实有数据在飞着
+            // 等它确认——否则纯粹的窗口更新（尤其是零窗口通告）会被当成丢包信号，
+            // 凑够3个就错误地触发快速重传
+            let seg_wnd = (tcph.window_size() as u32) << self.snd_wnd_shift;
+            let has_unacked_data = self.send.nxt != self.send.una;
+            if seg_wnd == self.send.wnd && has_unacked_data {
+                self.dup_acks += 1;
+                if self.dup_acks == 3 {
+                    self.dup_acks = 0;
+                    self.fast_retransmit(iface)?;
+                }
+            } else {
+                self.dup_acks = 0;
+            }
+        }
+
+        // 时间戳选项只在SYN里确认是否启用（accept里做的），但TSval要逐段刷新，
+        // 不然对方拿TSecr算出来的RTT永远是握手那一刻的，之后全错
+        if self.timestamps_enabled {
+            if let Some((tsval, _)) = parse_tcp_options(tcph.options()).timestamps {
+                self.ts_recent = tsval;
+            }
+        }
+
         // RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND , RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
         // A - B (我是A)
         // RCV.NXT: 接收端（A）期望接收的下一个字节的序列号,它表示 A 已成功接收到的数据的序列号加一
@@ -234,6 +783,18 @@ impl Connection {
 
         let seqn = tcph.sequence_number();
 
+        // RFC793 窗口更新规则：只有比上次用来更新窗口的段更新的段，才能拿来刷新 send.wnd
+        // (SND.WL1 < SEG.SEQ) or (SND.WL1 = SEG.SEQ and SND.WL2 =< SEG.ACK)
+        if (self.send.wl1 as u32) < seqn
+            || ((self.send.wl1 as u32) == seqn && (self.send.wl2 as u32) <= ackn)
+        {
+            self.send.wnd = (tcph.window_size() as u32) << self.snd_wnd_shift;
+            self.send.wl1 = seqn as usize;
+            self.send.wl2 = ackn as usize;
+            // 窗口可能刚刚从零变成非零，或者反过来变成了零：撤掉或者重新安排持续定时器
+            self.update_persist_timer();
+        }
+
         // or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
         let wend = self.recv.nxt.wrapping_add(self.recv.wnd as _);
         let mut slen = data.len() as u32;
@@ -244,6 +805,17 @@ impl Connection {
             slen += 1;
         }
 
+        // 对方的FIN我们已经消化过了（fin_seq清掉了），但对方没收到我们的ACK，又重发了一遍：
+        // 这个FIN的seqn正好是 recv.nxt-1，严格落在窗口之外，会被下面的 is_between_wrapped 拒掉。
+        // 不把ACK补发回去，对方会一直卡在LAST_ACK/FIN_WAIT2重发FIN，teardown永远完不成
+        if data.is_empty()
+            && tcph.fin()
+            && self.fin_seq.is_none()
+            && seqn == self.recv.nxt.wrapping_sub(1)
+        {
+            return self.write(iface, &[]).map(|_| ());
+        }
+
         if data.len() == 0 {
             if self.recv.wnd == 0 {
                 if seqn != self.recv.nxt {
@@ -267,11 +839,82 @@ impl Connection {
                 if !tcph.ack() {
                     return Ok(());
                 }
+                // 第三次握手的ACK到了，连接建立完成
+                self.state = State::Estab;
+            }
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::CloseWait
+            | State::LastAck => {}
+            State::TimeWait | State::Closed => return Ok(()),
+        }
+
+        // 我们自己的FIN是否已经被对端确认了，确认了的话把状态机往下推一格
+        match self.state {
+            State::FinWait1 => {
+                if matches!(self.closed_at, Some(closed_at) if self.send.una == closed_at) {
+                    self.state = State::FinWait2;
+                }
+            }
+            State::Closing => {
+                if matches!(self.closed_at, Some(closed_at) if self.send.una == closed_at) {
+                    self.state = State::TimeWait;
+                    self.time_wait_started = Some(Instant::now());
+                }
+            }
+            State::LastAck => {
+                if matches!(self.closed_at, Some(closed_at) if self.send.una == closed_at) {
+                    self.state = State::Closed;
+                }
             }
-            State::Estab => {}
             _ => {}
         }
 
+        if !data.is_empty() {
+            self.insert_segment(seqn, data);
+        }
+
+        if tcph.fin() {
+            // FIN 占用一个序列号，紧跟在这个段的数据之后
+            self.fin_seq = Some(seqn.wrapping_add(data.len() as u32));
+        }
+
+        // 只有 recv.nxt 真正追上了FIN的位置（前面的数据都拼齐了），才能越过FIN、ACK它
+        if let Some(fin_seq) = self.fin_seq {
+            if self.recv.nxt == fin_seq {
+                match self.state {
+                    State::Closed | State::TimeWait => {}
+                    _ => {
+                        self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                        self.fin_seq = None;
+
+                        self.state = match mem::take(&mut self.state) {
+                            State::Estab => State::CloseWait,
+                            State::FinWait1 => {
+                                // 对方的FIN和我方FIN的ACK同时到达，也可能先到
+                                if matches!(self.closed_at, Some(closed_at) if self.send.una == closed_at)
+                                {
+                                    self.time_wait_started = Some(Instant::now());
+                                    State::TimeWait
+                                } else {
+                                    State::Closing
+                                }
+                            }
+                            State::FinWait2 => {
+                                self.time_wait_started = Some(Instant::now());
+                                State::TimeWait
+                            }
+                            other => other,
+                        };
+
+                        self.write(iface, &[])?;
+                    }
+                }
+            }
+        }
+
         println!(
             "{}:{} -> {}:{} {}b of tcp",
             iph.source_addr(),